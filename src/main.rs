@@ -1,12 +1,38 @@
-use std::net::{SocketAddr, UdpSocket};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-const DNS_SERVER: &'static str = "192.168.1.254:53";
+/// Upstream resolvers, tried in order with retransmission and failover if
+/// one is slow or unresponsive
+const DNS_SERVERS: &[&str] = &["192.168.1.254:53"];
+
+/// Path to the zone file loaded into the local [Authority] at startup
+const ZONE_FILE: &str = "zones.txt";
+
+/// Source of transaction IDs for outgoing forward queries, so a response
+/// can be matched back to the query that's still waiting on it and a late
+/// reply to an abandoned query doesn't get handed to the wrong client
+static NEXT_TRANSACTION_ID: AtomicU16 = AtomicU16::new(0);
+
+fn next_transaction_id() -> [u8; 2] {
+    NEXT_TRANSACTION_ID.fetch_add(1, Ordering::Relaxed).to_be_bytes()
+}
 
 #[derive(Debug)]
 enum Error{
     DnsLabelTooLong,
     DnsNameTooLong,
     OnlyOneQuestionSupported,
+    TooManyNameIndirections,
+    /// Every upstream resolver timed out or failed within the retry budget
+    AllResolversFailed,
+    /// A packet ended before a length/offset the parser needed to read
+    /// implied it should. Reachable with any malformed or truncated
+    /// packet, so this must be returned rather than panicking
+    TruncatedPacket,
 }
 
 type Result<T> = std::result::Result<T, self::Error>;
@@ -14,76 +40,468 @@ type Result<T> = std::result::Result<T, self::Error>;
 fn main() {
 
     let socket = UdpSocket::bind("0.0.0.0:53").unwrap();
-    let forward_dns_server: SocketAddr = DNS_SERVER.parse().unwrap();
     let forward_socket = UdpSocket::bind("0.0.0.0:5335").unwrap();
-    forward_socket.connect(forward_dns_server).unwrap();
+    let resolvers: Vec<SocketAddr> = DNS_SERVERS.iter().map(|s| s.parse().unwrap()).collect();
+    let authority = Arc::new(Authority::load(ZONE_FILE));
+    let cache = Arc::new(Mutex::new(Cache::default()));
+
+    {
+        let authority = Arc::clone(&authority);
+        let cache = Arc::clone(&cache);
+        let resolvers = resolvers.clone();
+        std::thread::spawn(move || run_tcp_listener(authority, cache, resolvers));
+    }
 
     loop {
 
-        // Wait for a UDP Packet
-        let mut recv_buf = [0u8; 512];
-        let src_addr = match socket.recv_from(&mut recv_buf){
-            Ok((_, src_addr)) => src_addr,
+        // Wait for a UDP Packet. We don't yet know what payload size the
+        // client can accept, so receive into a buffer big enough for the
+        // largest EDNS(0) payload we support
+        let mut recv_buf = vec![0u8; Dns::MAX_UDP_PAYLOAD_SIZE as usize];
+        let (recv_len, src_addr) = match socket.recv_from(&mut recv_buf){
+            Ok((recv_len, src_addr)) => (recv_len, src_addr),
             _ => {
                 continue
             }
         };
         // Try to parse
-        let mut request = match Dns::parse(&recv_buf){
+        let mut request = match Dns::parse(&recv_buf[..recv_len]){
             Ok(request) => request,
             Err(err) => {
                 println!("{err:?}");
                 continue;
             }
         };
-    
-        // We dont know about it, forward it to next resolver
-        let mut recv_buf = [0u8; 512];
-        let mut send_buf = [0u8; 512];
-        let forward_request = Dns::request(request.query);
-        let len = forward_request.serialise(&mut send_buf).unwrap();
-        forward_socket.send(&send_buf[..len]).unwrap();
-        forward_socket.recv(&mut recv_buf).unwrap();
-        // Try to parse response from forward DNS server
-        let forward_response = match Dns::parse(&recv_buf){
+        // The client's advertised EDNS(0) UDP payload size, or the legacy
+        // 512-byte limit if it didn't send an OPT record. If the answer
+        // doesn't fit, `serialise` sets the TC flag and the client is
+        // expected to retry over TCP
+        let client_udp_payload_size = request
+            .edns_udp_payload_size()
+            .unwrap_or(Dns::DEFAULT_UDP_PAYLOAD_SIZE)
+            .clamp(Dns::DEFAULT_UDP_PAYLOAD_SIZE, Dns::MAX_UDP_PAYLOAD_SIZE)
+            as usize;
+
+        if let Err(err) = resolve(&mut request, &authority, &cache, &resolvers, &forward_socket) {
+            println!("{err:?}");
+            continue;
+        }
+
+        let mut send_buf = vec![0u8; client_udp_payload_size];
+        let len = request.serialise(&mut send_buf).unwrap();
+        socket.send_to(&send_buf[..len], src_addr).unwrap();
+    }
+}
+
+/// Resolve `request` in place into its response: an authoritative [Zone]
+/// answer, a live [Cache] hit, or a round-trip to the upstream forward
+/// resolvers (whose response is cached for next time). Shared by both the
+/// UDP loop and [run_tcp_listener]
+fn resolve(
+    request: &mut Dns,
+    authority: &Authority,
+    cache: &Mutex<Cache>,
+    resolvers: &[SocketAddr],
+    forward_socket: &UdpSocket,
+) -> Result<()> {
+
+    // If we are authoritative for this name, answer locally (or NXDOMAIN)
+    // without ever touching the forward socket
+    if let Some(zone) = authority.find_zone(request.query.name.as_bytes()) {
+        let answer = zone.lookup(&request.query);
+        request.respond_local(answer, zone.soa_answer());
+        return Ok(());
+    }
+
+    // Serve from cache if we already have a live answer for this name
+    let cache_key = Cache::key(&request.query);
+    if let Some(entry) = cache.lock().unwrap().get(&cache_key) {
+        request.respond_from_cache(&entry);
+        return Ok(());
+    }
+
+    // We dont know about it, forward it to the upstream resolvers,
+    // advertising our own EDNS(0) payload size so large upstream
+    // responses aren't truncated
+    let forward_request = Dns::request(request.query, next_transaction_id());
+    match forward(&forward_request, resolvers, forward_socket) {
+        Ok(forward_response) => {
+            println!("{forward_response:?}");
+            cache.lock().unwrap().insert(cache_key, &forward_response);
+            request.respond(&forward_response);
+        }
+        Err(err) => {
+            println!("{err:?}");
+            request.respond_servfail();
+        }
+    }
+    Ok(())
+}
+
+/// Send `forward_request` to each of `resolvers` in turn like a stub
+/// resolver: an initial [Dns::FORWARD_INITIAL_TIMEOUT] wait, doubling on
+/// every retransmit, cycling through the resolver list, until either a
+/// matching response arrives or [Dns::FORWARD_MAX_TIMEOUT] of total
+/// waiting has passed. Replies from the wrong resolver, that don't parse,
+/// or whose transaction ID doesn't match (a late response to an
+/// abandoned query) are ignored rather than handed back
+fn forward(forward_request: &Dns, resolvers: &[SocketAddr], forward_socket: &UdpSocket) -> Result<Dns> {
+    let mut send_buf = vec![0u8; Dns::MAX_UDP_PAYLOAD_SIZE as usize];
+    let len = forward_request.clone().serialise(&mut send_buf)?;
+
+    let mut timeout = Dns::FORWARD_INITIAL_TIMEOUT;
+    let mut elapsed = Duration::ZERO;
+    let overall_deadline = Instant::now() + Dns::FORWARD_MAX_TIMEOUT;
+
+    for resolver in resolvers.iter().cycle() {
+        if elapsed >= Dns::FORWARD_MAX_TIMEOUT || Instant::now() >= overall_deadline {
+            break;
+        }
+
+        if forward_socket.send_to(&send_buf[..len], resolver).is_err() {
+            continue;
+        }
+        if forward_socket.set_read_timeout(Some(timeout)).is_err() {
+            continue;
+        }
+
+        // A stream of stray packets (wrong source, unparseable, or a late
+        // reply to an abandoned query) would otherwise keep resetting
+        // `recv_from`'s own timeout indefinitely, so bound the whole
+        // attempt by the same wall-clock deadline rather than trusting
+        // the per-call socket timeout alone
+        let mut recv_buf = vec![0u8; Dns::MAX_UDP_PAYLOAD_SIZE as usize];
+        while Instant::now() < overall_deadline {
+            let Ok((recv_len, src_addr)) = forward_socket.recv_from(&mut recv_buf) else {
+                break;
+            };
+            if src_addr != *resolver {
+                continue;
+            }
+            let Ok(response) = Dns::parse(&recv_buf[..recv_len]) else {
+                continue;
+            };
+            if response.transaction_id != forward_request.transaction_id {
+                continue;
+            }
+            return Ok(response);
+        }
+
+        elapsed += timeout;
+        timeout = (timeout * 2).min(Dns::FORWARD_MAX_TIMEOUT.saturating_sub(elapsed));
+    }
+
+    Err(Error::AllResolversFailed)
+}
+
+/// Parallel TCP listener on port 53: every response that gets truncated
+/// (TC flag set) over UDP is expected to be retried here. Each connection
+/// is served on its own thread, sharing the same [Authority]/[Cache] and
+/// the same [resolve] path as the UDP loop, with its own forward socket so
+/// concurrent connections can't see each other's replies
+fn run_tcp_listener(authority: Arc<Authority>, cache: Arc<Mutex<Cache>>, resolvers: Vec<SocketAddr>) {
+    let listener = TcpListener::bind("0.0.0.0:53").unwrap();
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let authority = Arc::clone(&authority);
+        let cache = Arc::clone(&cache);
+        let resolvers = resolvers.clone();
+        std::thread::spawn(move || handle_tcp_connection(stream, &authority, &cache, &resolvers));
+    }
+}
+
+/// Serve DNS-over-TCP messages on `stream`: each message is prefixed with
+/// its 2-byte length, per RFC 1035 section 4.2.2
+fn handle_tcp_connection(
+    mut stream: TcpStream,
+    authority: &Authority,
+    cache: &Mutex<Cache>,
+    resolvers: &[SocketAddr],
+) {
+    let Ok(forward_socket) = UdpSocket::bind("0.0.0.0:0") else {
+        return;
+    };
+
+    loop {
+        let mut length_prefix = [0u8; 2];
+        if stream.read_exact(&mut length_prefix).is_err() {
+            return;
+        }
+        let mut payload = vec![0u8; u16::from_be_bytes(length_prefix) as usize];
+        if stream.read_exact(&mut payload).is_err() {
+            return;
+        }
+
+        let mut request = match Dns::parse(&payload) {
             Ok(request) => request,
             Err(err) => {
                 println!("{err:?}");
                 continue;
             }
         };
-        println!("{forward_response:?}");
-        // Respond to client
-        let mut send_buf = [0u8; 512];
-        request.respond(&forward_response);
-        let len = request.serialise(&mut send_buf).unwrap();
-        socket.send_to(&send_buf[..len], src_addr).unwrap();
+
+        if let Err(err) = resolve(&mut request, authority, cache, resolvers, &forward_socket) {
+            println!("{err:?}");
+            continue;
+        }
+
+        // TCP responses have no EDNS(0) payload limit to negotiate, so
+        // serialise into a buffer big enough to never need truncating
+        let mut send_buf = vec![0u8; u16::MAX as usize];
+        let Ok(len) = request.serialise(&mut send_buf) else {
+            continue;
+        };
+        if stream.write_all(&(len as u16).to_be_bytes()).is_err() {
+            return;
+        }
+        if stream.write_all(&send_buf[..len]).is_err() {
+            return;
+        }
+    }
+}
+
+/// A domain name in its expanded wire format: length-prefixed labels
+/// terminated by a zero-length label, with any compression pointers
+/// already resolved
+#[derive(Debug, Clone, Copy)]
+struct Name {
+    bytes: [u8; 253],
+    len: usize,
+}
+
+impl Name {
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// Lowercased wire bytes, for case-insensitive comparison/hashing
+    /// (RFC 1035 §3.1). Safe to lowercase indiscriminately: label-length
+    /// bytes (0-63) never collide with ASCII letter bytes
+    fn key_bytes(&self) -> Vec<u8> {
+        self.as_bytes().iter().map(u8::to_ascii_lowercase).collect()
+    }
+
+    /// Encode a textual domain name (e.g. `www.example.com.`) into wire
+    /// format
+    fn from_str(name: &str) -> Result<Self> {
+        let mut bytes = [0u8; 253];
+        let mut len = 0;
+        for label in name.trim_end_matches('.').split('.') {
+            if label.len() > 63 {
+                return Err(Error::DnsLabelTooLong);
+            }
+            bytes[len] = label.len() as u8;
+            len += 1;
+            bytes[len..len + label.len()].copy_from_slice(label.as_bytes());
+            len += label.len();
+            if len > 253 {
+                return Err(Error::DnsNameTooLong);
+            }
+        }
+        bytes[len] = 0x00;
+        len += 1;
+        Ok(Self { bytes, len })
+    }
+
+    /// The root name (`.`), used as the owner name of an EDNS(0) OPT
+    /// pseudo-record
+    fn root() -> Self {
+        let mut bytes = [0u8; 253];
+        bytes[0] = 0x00;
+        Self { bytes, len: 1 }
+    }
+
+    /// Reconstruct a [Name] from wire bytes already known to be valid and
+    /// within the 253-byte limit, e.g. another [Name]'s own `as_bytes()`
+    fn from_wire_bytes(wire_bytes: &[u8]) -> Self {
+        let mut bytes = [0u8; 253];
+        bytes[..wire_bytes.len()].copy_from_slice(wire_bytes);
+        Self { bytes, len: wire_bytes.len() }
+    }
+}
+
+/// DNS record (`TYPE`) codes, as used in both the question and resource
+/// record sections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+enum QueryType {
+    A,
+    NS,
+    CNAME,
+    SOA,
+    PTR,
+    MX,
+    TXT,
+    AAAA,
+    SRV,
+    /// EDNS(0) pseudo-record (RFC 6891)
+    Opt,
+    Unknown(u16),
+}
+
+impl QueryType {
+    fn from_num(ty: u16) -> Self {
+        match ty {
+            1 => Self::A,
+            2 => Self::NS,
+            5 => Self::CNAME,
+            6 => Self::SOA,
+            12 => Self::PTR,
+            15 => Self::MX,
+            16 => Self::TXT,
+            28 => Self::AAAA,
+            33 => Self::SRV,
+            41 => Self::Opt,
+            other => Self::Unknown(other),
+        }
+    }
+
+    fn to_num(self) -> u16 {
+        match self {
+            Self::A => 1,
+            Self::NS => 2,
+            Self::CNAME => 5,
+            Self::SOA => 6,
+            Self::PTR => 12,
+            Self::MX => 15,
+            Self::TXT => 16,
+            Self::AAAA => 28,
+            Self::SRV => 33,
+            Self::Opt => 41,
+            Self::Unknown(ty) => ty,
+        }
+    }
+}
+
+/// The parsed RDATA of a resource record, one variant per supported
+/// [QueryType]
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms, clippy::large_enum_variant)]
+enum RecordData {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    CNAME(Name),
+    NS(Name),
+    MX{ preference: u16, exchange: Name },
+    TXT(Vec<u8>),
+    SOA{
+        m_name: Name,
+        r_name: Name,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    PTR(Name),
+    SRV{ priority: u16, weight: u16, port: u16, target: Name },
+    /// EDNS(0) OPT pseudo-record options; the advertised UDP payload size
+    /// and packed extended-rcode/version/flags live in the owning
+    /// [Answer]'s `class` and `ttl` fields rather than here
+    Opt(Vec<u8>),
+    Unknown{ ty: u16, raw: Vec<u8> },
+}
+
+impl RecordData {
+    fn ty(&self) -> u16 {
+        match self {
+            Self::A(_) => QueryType::A.to_num(),
+            Self::AAAA(_) => QueryType::AAAA.to_num(),
+            Self::CNAME(_) => QueryType::CNAME.to_num(),
+            Self::NS(_) => QueryType::NS.to_num(),
+            Self::MX{..} => QueryType::MX.to_num(),
+            Self::TXT(_) => QueryType::TXT.to_num(),
+            Self::SOA{..} => QueryType::SOA.to_num(),
+            Self::PTR(_) => QueryType::PTR.to_num(),
+            Self::SRV{..} => QueryType::SRV.to_num(),
+            Self::Opt(_) => QueryType::Opt.to_num(),
+            Self::Unknown{ ty, .. } => *ty,
+        }
+    }
+
+    /// Serialise this record's RDATA, returning the bytes written (the
+    /// RDLENGTH to put on the wire)
+    fn serialise(&self, buf: &mut [u8], buf_ptr: &mut usize) -> u16 {
+        let start = *buf_ptr;
+        match self {
+            Self::A(addr) => Dns::append_to_buffer(buf, buf_ptr, &addr.octets()),
+            Self::AAAA(addr) => Dns::append_to_buffer(buf, buf_ptr, &addr.octets()),
+            Self::CNAME(name) | Self::NS(name) | Self::PTR(name) => {
+                Dns::append_to_buffer(buf, buf_ptr, name.as_bytes())
+            }
+            Self::MX{ preference, exchange } => {
+                Dns::append_to_buffer(buf, buf_ptr, &preference.to_be_bytes());
+                Dns::append_to_buffer(buf, buf_ptr, exchange.as_bytes());
+            }
+            Self::TXT(raw) => Dns::append_to_buffer(buf, buf_ptr, raw),
+            Self::SOA{ m_name, r_name, serial, refresh, retry, expire, minimum } => {
+                Dns::append_to_buffer(buf, buf_ptr, m_name.as_bytes());
+                Dns::append_to_buffer(buf, buf_ptr, r_name.as_bytes());
+                Dns::append_to_buffer(buf, buf_ptr, &serial.to_be_bytes());
+                Dns::append_to_buffer(buf, buf_ptr, &refresh.to_be_bytes());
+                Dns::append_to_buffer(buf, buf_ptr, &retry.to_be_bytes());
+                Dns::append_to_buffer(buf, buf_ptr, &expire.to_be_bytes());
+                Dns::append_to_buffer(buf, buf_ptr, &minimum.to_be_bytes());
+            }
+            Self::SRV{ priority, weight, port, target } => {
+                Dns::append_to_buffer(buf, buf_ptr, &priority.to_be_bytes());
+                Dns::append_to_buffer(buf, buf_ptr, &weight.to_be_bytes());
+                Dns::append_to_buffer(buf, buf_ptr, &port.to_be_bytes());
+                Dns::append_to_buffer(buf, buf_ptr, target.as_bytes());
+            }
+            Self::Opt(raw) => Dns::append_to_buffer(buf, buf_ptr, raw),
+            Self::Unknown{ raw, .. } => Dns::append_to_buffer(buf, buf_ptr, raw),
+        }
+        (*buf_ptr - start) as u16
+    }
+
+    /// Number of bytes this record's RDATA will occupy on the wire, used
+    /// to decide whether a record still fits before actually writing it
+    fn wire_len(&self) -> usize {
+        match self {
+            Self::A(_) => 4,
+            Self::AAAA(_) => 16,
+            Self::CNAME(name) | Self::NS(name) | Self::PTR(name) => name.len,
+            Self::MX{ exchange, .. } => 2 + exchange.len,
+            Self::TXT(raw) => raw.len(),
+            Self::SOA{ m_name, r_name, .. } => m_name.len + r_name.len + 20,
+            Self::SRV{ target, .. } => 6 + target.len,
+            Self::Opt(raw) => raw.len(),
+            Self::Unknown{ raw, .. } => raw.len(),
+        }
     }
 }
 
 /// Struct for storing the data from a DNS query inside a [Dns]
 #[derive(Debug, Clone, Copy)]
 struct Query{
-    domain_name: [u8; 253],
-    domain_name_len: usize,
+    name: Name,
     ty: u16,
     class: u16,
 }
 
-/// Struct for storing the data from a DNS query inside a [Dns]
-#[derive(Debug, Clone, Copy)]
+/// Struct for storing the data from a DNS resource record (answer,
+/// authority or additional section) inside a [Dns]
+#[derive(Debug, Clone)]
 struct Answer{
-    name: [u8; 2],
-    ty: u16,
+    name: Name,
     class: u16,
     ttl: u32,
-    len: u16,
-    address: [u8; 4],
+    data: RecordData,
 }
 
+impl Answer {
+    /// Number of bytes this record occupies on the wire: NAME, TYPE,
+    /// CLASS, TTL, RDLENGTH and RDATA
+    fn wire_len(&self) -> usize {
+        self.name.len + 2 + 2 + 4 + 2 + self.data.wire_len()
+    }
+}
 
 /// Struct for storing the data from a DNS Request
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Dns {
     transaction_id: [u8; 2],
     flags: u16,
@@ -92,109 +510,220 @@ struct Dns {
     authority_records: u16,
     additional_records: u16,
     query: Query,
-    answer: Option<Answer>
+    /// All resource records from the answer, authority and additional
+    /// sections, in wire order. Which section a record belongs to is
+    /// determined by its position against `answer_records` /
+    /// `authority_records` / `additional_records`
+    answers: Vec<Answer>,
 }
 
 impl Dns {
 
     const DNS_FLAG_RESPONSE: u16 = (1 << 15) as u16;
-    
-    fn parse(payload: &[u8; 512]) -> Result<Self> {
-        let transaction_id: [u8; 2] = [payload[0], payload[1]];
-        let flags: u16 = (payload[2] as u16) << 8 | payload[3] as u16;
-        let questions: u16 = (payload[4] as u16) << 8 | payload[5] as u16;
 
-        if questions > 1 {
-            return Err(Error::OnlyOneQuestionSupported);
-        }
+    /// TC (truncation) bit: set when the answer had to be cut short to fit
+    /// the client's UDP payload size, telling it to retry over TCP
+    const DNS_FLAG_TRUNCATED: u16 = 1 << 9;
 
-        let answer_records: u16 = (payload[6] as u16) << 8 | payload[7] as u16;
-        let authority_records: u16 = (payload[8] as u16) << 8 | payload[9] as u16;
-        let additional_records: u16 = (payload[10] as u16) << 8 | payload[11] as u16;
+    /// Maximum number of compression-pointer jumps [Self::read_name] will
+    /// follow before giving up, so a crafted pointer loop can't hang us
+    const MAX_NAME_INDIRECTIONS: usize = 16;
+
+    /// The pre-EDNS(0) UDP payload size, used when a client sends no OPT
+    /// record
+    const DEFAULT_UDP_PAYLOAD_SIZE: u16 = 512;
+
+    /// The EDNS(0) UDP payload size we advertise and are willing to
+    /// buffer for
+    const MAX_UDP_PAYLOAD_SIZE: u16 = 4096;
+
+    /// Initial per-resolver timeout before retransmitting to the next
+    /// upstream resolver
+    const FORWARD_INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Total time budget across every retry before giving up and
+    /// returning SERVFAIL to the client
+    const FORWARD_MAX_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// A single byte at `at`, or [Error::TruncatedPacket] instead of
+    /// panicking if `payload` doesn't reach that far — this parser is fed
+    /// packets from anyone who can reach the socket, so every offset into
+    /// `payload` must be checked rather than indexed directly
+    fn byte(payload: &[u8], at: usize) -> Result<u8> {
+        payload.get(at).copied().ok_or(Error::TruncatedPacket)
+    }
+
+    /// A big-endian `u16` at `at`
+    fn u16_at(payload: &[u8], at: usize) -> Result<u16> {
+        Ok((Self::byte(payload, at)? as u16) << 8 | Self::byte(payload, at + 1)? as u16)
+    }
+
+    /// A big-endian `u32` at `at`
+    fn u32_at(payload: &[u8], at: usize) -> Result<u32> {
+        Ok(u32::from_be_bytes([
+            Self::byte(payload, at)?,
+            Self::byte(payload, at + 1)?,
+            Self::byte(payload, at + 2)?,
+            Self::byte(payload, at + 3)?,
+        ]))
+    }
+
+    /// A slice of `len` bytes starting at `at`
+    fn slice_at(payload: &[u8], at: usize, len: usize) -> Result<&[u8]> {
+        payload.get(at..at + len).ok_or(Error::TruncatedPacket)
+    }
+
+    /// Read a (possibly compressed) domain name starting at `start`.
+    ///
+    /// Returns the fully expanded [Name] (any `0xC0` pointers followed and
+    /// resolved) and the offset in `payload` just past the name as it was
+    /// originally encoded at `start` (i.e. past the 2-byte pointer, for a
+    /// name that starts with one).
+    fn read_name(payload: &[u8], start: usize) -> Result<(Name, usize)> {
+        let mut bytes = [0u8; 253];
+        let mut len = 0;
+        let mut cursor = start;
+        let mut end = None;
+        let mut indirections = 0;
 
-        // Parse Query
-        let domain_name_start = 12;
-        let mut domain_name = [0u8; 253];
-        let mut domain_name_pointer = 0;
         loop {
-            let label_len: usize = payload[
-                domain_name_start + domain_name_pointer
-            ] as usize;
+            let label_len = Self::byte(payload, cursor)? as usize;
+
+            // A pointer: top two bits set, low 14 bits are an absolute
+            // offset from the start of the packet to jump to
+            if label_len & 0xC0 == 0xC0 {
+                indirections += 1;
+                if indirections > Self::MAX_NAME_INDIRECTIONS {
+                    return Err(Error::TooManyNameIndirections);
+                }
+                // The outer cursor only ever advances past the first
+                // pointer it meets; every jump after that is internal
+                end.get_or_insert(cursor + 2);
+                cursor = ((label_len & 0x3F) << 8) | Self::byte(payload, cursor + 1)? as usize;
+                continue;
+            }
 
             // Labels cannot exceed 63 characters
             if label_len > 63 {
                 return Err(Error::DnsLabelTooLong);
             }
 
-            domain_name_pointer += label_len + 1;
-
             // DNS name max size is 253
-            if domain_name_pointer > 253{
+            if len + label_len + 1 > 253 {
                 return Err(Error::DnsNameTooLong);
             }
 
+            bytes[len..len + label_len + 1]
+                .copy_from_slice(Self::slice_at(payload, cursor, label_len + 1)?);
+            len += label_len + 1;
+            cursor += label_len + 1;
+
             // No more labels
             if label_len == 0x00 {
-                domain_name[..domain_name_pointer].copy_from_slice(
-                    &payload[
-                        domain_name_start ..
-                        domain_name_start + domain_name_pointer
-                    ]
-                );
+                end.get_or_insert(cursor);
                 break;
             }
         }
-        let domain_name_end = domain_name_start + domain_name_pointer;
-        let query_ty =  
-            (payload[domain_name_end] as u16) << 8 | 
-            payload[domain_name_end + 1] as u16;
-        let query_class = 
-            (payload[domain_name_end + 2] as u16) << 8 | 
-            payload[domain_name_end + 3] as u16;
-        let query_end = domain_name_end + 4;
-        // Parse answer
-        let answer = if answer_records > 0 {
-            let name: [u8; 2] = [
-                payload[query_end + 0],
-                payload[query_end + 1],
-            ];
-            let ty: [u8; 2] = [
-                payload[query_end + 2],
-                payload[query_end + 3],
-            ];
-            let class: [u8; 2] = [
-                payload[query_end + 4],
-                payload[query_end + 5],
-            ];
-            let ttl: [u8; 4] = [
-                payload[query_end + 6],
-                payload[query_end + 7],
-                payload[query_end + 8],
-                payload[query_end + 9],
-            ];
-            let len: [u8; 2] = [
-                payload[query_end + 10],
-                payload[query_end + 11],
-            ];
-            let address: [u8; 4] = [
-                payload[query_end + 12],
-                payload[query_end + 13],
-                payload[query_end + 14],
-                payload[query_end + 15],
-            ];
-            Some(Answer{
-                name,
-                ty: u16::from_be_bytes(ty),
-                class: u16::from_be_bytes(class),
-                ttl: u32::from_be_bytes(ttl),
-                len: u16::from_be_bytes(len),
-                address,
-            })
-        }else{
-            None
+
+        Ok((Name { bytes, len }, end.unwrap()))
+    }
+
+    /// Read a single resource record (name, type, class, ttl, rdata)
+    /// starting at `start`, returning it and the offset just past it
+    fn read_record(payload: &[u8], start: usize) -> Result<(Answer, usize)> {
+        let (name, name_end) = Self::read_name(payload, start)?;
+        let ty = Self::u16_at(payload, name_end)?;
+        let class = Self::u16_at(payload, name_end + 2)?;
+        let ttl = Self::u32_at(payload, name_end + 4)?;
+        let rdlength = Self::u16_at(payload, name_end + 8)? as usize;
+        let rdata_start = name_end + 10;
+        let rdata_end = rdata_start + rdlength;
+        // Check the declared RDATA bounds up front so every plain slice
+        // of `payload[rdata_start..rdata_end]` below is known to be safe
+        Self::slice_at(payload, rdata_start, rdlength)?;
+
+        let data = match QueryType::from_num(ty) {
+            QueryType::A => {
+                let octets = Self::slice_at(payload, rdata_start, 4)?;
+                RecordData::A(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+            }
+            QueryType::AAAA => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(Self::slice_at(payload, rdata_start, 16)?);
+                RecordData::AAAA(Ipv6Addr::from(octets))
+            }
+            QueryType::CNAME => RecordData::CNAME(Self::read_name(payload, rdata_start)?.0),
+            QueryType::NS => RecordData::NS(Self::read_name(payload, rdata_start)?.0),
+            QueryType::PTR => RecordData::PTR(Self::read_name(payload, rdata_start)?.0),
+            QueryType::MX => {
+                let preference = Self::u16_at(payload, rdata_start)?;
+                let (exchange, _) = Self::read_name(payload, rdata_start + 2)?;
+                RecordData::MX{ preference, exchange }
+            }
+            QueryType::TXT => RecordData::TXT(payload[rdata_start..rdata_end].to_vec()),
+            QueryType::SOA => {
+                let (m_name, after_m_name) = Self::read_name(payload, rdata_start)?;
+                let (r_name, after_r_name) = Self::read_name(payload, after_m_name)?;
+                RecordData::SOA{
+                    m_name,
+                    r_name,
+                    serial: Self::u32_at(payload, after_r_name)?,
+                    refresh: Self::u32_at(payload, after_r_name + 4)?,
+                    retry: Self::u32_at(payload, after_r_name + 8)?,
+                    expire: Self::u32_at(payload, after_r_name + 12)?,
+                    minimum: Self::u32_at(payload, after_r_name + 16)?,
+                }
+            }
+            QueryType::SRV => {
+                let priority = Self::u16_at(payload, rdata_start)?;
+                let weight = Self::u16_at(payload, rdata_start + 2)?;
+                let port = Self::u16_at(payload, rdata_start + 4)?;
+                let (target, _) = Self::read_name(payload, rdata_start + 6)?;
+                RecordData::SRV{ priority, weight, port, target }
+            }
+            QueryType::Opt => RecordData::Opt(payload[rdata_start..rdata_end].to_vec()),
+            QueryType::Unknown(ty) => RecordData::Unknown{
+                ty,
+                raw: payload[rdata_start..rdata_end].to_vec(),
+            },
         };
 
-        Ok(Self { 
+        Ok((Answer{ name, class, ttl, data }, rdata_end))
+    }
+
+    fn parse(payload: &[u8]) -> Result<Self> {
+        let transaction_id: [u8; 2] = [Self::byte(payload, 0)?, Self::byte(payload, 1)?];
+        let flags = Self::u16_at(payload, 2)?;
+        let questions = Self::u16_at(payload, 4)?;
+
+        if questions > 1 {
+            return Err(Error::OnlyOneQuestionSupported);
+        }
+
+        let answer_records = Self::u16_at(payload, 6)?;
+        let authority_records = Self::u16_at(payload, 8)?;
+        let additional_records = Self::u16_at(payload, 10)?;
+
+        // Parse Query
+        let domain_name_start = 12;
+        let (name, domain_name_end) = Self::read_name(payload, domain_name_start)?;
+        let query_ty = Self::u16_at(payload, domain_name_end)?;
+        let query_class = Self::u16_at(payload, domain_name_end + 2)?;
+        let mut cursor = domain_name_end + 4;
+
+        // Parse every resource record across the answer, authority and
+        // additional sections in one pass, in wire order
+        let total_records = answer_records as usize
+            + authority_records as usize
+            + additional_records as usize;
+        let mut answers = Vec::with_capacity(total_records);
+        for _ in 0..total_records {
+            let (answer, end) = Self::read_record(payload, cursor)?;
+            answers.push(answer);
+            cursor = end;
+        }
+
+        Ok(Self {
             transaction_id,
             flags,
             questions,
@@ -202,46 +731,121 @@ impl Dns {
             authority_records,
             additional_records,
             query: Query{
-                domain_name,
-                domain_name_len: domain_name_pointer,
+                name,
                 ty: query_ty,
                 class: query_class,
             },
-            answer,
+            answers,
         })
     }
-    
-    /// Craft a [Dns] request
-    fn request(query: Query) -> Self {
-
-        Self { 
-            transaction_id: [0x13, 0x37], 
-            flags: 0x100, 
-            questions: 1, 
-            answer_records: 0, 
-            authority_records: 0, 
-            additional_records: 0, 
+
+    /// Craft a [Dns] request, advertising our own EDNS(0) UDP payload size
+    /// via an OPT record in the additional section. `transaction_id`
+    /// should come from [next_transaction_id] so the reply can be matched
+    /// back to this query
+    fn request(query: Query, transaction_id: [u8; 2]) -> Self {
+
+        Self {
+            transaction_id,
+            flags: 0x100,
+            questions: 1,
+            answer_records: 0,
+            authority_records: 0,
+            additional_records: 1,
             query,
-            answer: None,
+            answers: vec![Self::opt_record(Self::MAX_UDP_PAYLOAD_SIZE)],
         }
 
     }
 
+    /// Build an EDNS(0) OPT pseudo-record advertising `udp_payload_size`,
+    /// with no extended flags or options set
+    fn opt_record(udp_payload_size: u16) -> Answer {
+        Answer {
+            name: Name::root(),
+            class: udp_payload_size,
+            ttl: 0,
+            data: RecordData::Opt(Vec::new()),
+        }
+    }
+
+    /// The UDP payload size advertised via an EDNS(0) OPT record in the
+    /// answer, authority or additional sections, if any
+    fn edns_udp_payload_size(&self) -> Option<u16> {
+        self.answers.iter().find_map(|answer| match answer.data {
+            RecordData::Opt(_) => Some(answer.class),
+            _ => None,
+        })
+    }
+
+    /// Respond to the client by passing through everything we got back
+    /// from the forward DNS server, including its flags (RCODE, AA, RA,
+    /// ...) and the authority and additional sections
     fn respond(&mut self, response_from_forward_dns: &Self) {
-        self.flags = Self::DNS_FLAG_RESPONSE;
-        self.answer_records = 1;
+        self.flags = response_from_forward_dns.flags;
+        self.answer_records = response_from_forward_dns.answer_records;
+        self.authority_records = response_from_forward_dns.authority_records;
+        self.additional_records = response_from_forward_dns.additional_records;
+        self.answers = response_from_forward_dns.answers.clone();
+    }
+
+    const DNS_RCODE_NXDOMAIN: u16 = 3;
+    const DNS_RCODE_SERVFAIL: u16 = 2;
+
+    /// Answer the request with SERVFAIL, e.g. when every upstream
+    /// resolver timed out or failed
+    fn respond_servfail(&mut self) {
+        self.flags = Self::DNS_FLAG_RESPONSE | Self::DNS_RCODE_SERVFAIL;
+        self.answer_records = 0;
         self.authority_records = 0;
         self.additional_records = 0;
-        self.answer = response_from_forward_dns.answer;
+        self.answers = Vec::new();
     }
 
-    /// Takes a [u8; 512] buffer and writes the response based on the requests
-    fn serialise(self, buf: &mut [u8; 512]) -> Result<usize> {
+    /// Answer the request from an authoritative [Zone] lookup, setting
+    /// NXDOMAIN and the zone's SOA (in the authority section, per
+    /// RFC 2308 §3) when the zone has no matching record
+    fn respond_local(&mut self, answer: Option<Answer>, soa: Answer) {
+        self.additional_records = 0;
+        match answer {
+            Some(answer) => {
+                self.flags = Self::DNS_FLAG_RESPONSE;
+                self.answer_records = 1;
+                self.authority_records = 0;
+                self.answers = vec![answer];
+            }
+            None => {
+                self.flags = Self::DNS_FLAG_RESPONSE | Self::DNS_RCODE_NXDOMAIN;
+                self.answer_records = 0;
+                self.authority_records = 1;
+                self.answers = vec![soa];
+            }
+        }
+    }
+
+    /// Answer the request from a [CacheEntry], with TTLs already
+    /// decremented by however long it's been sat in the [Cache]
+    fn respond_from_cache(&mut self, entry: &CacheEntry) {
+        self.flags = Self::DNS_FLAG_RESPONSE;
+        self.answer_records = entry.answer_records;
+        self.authority_records = entry.authority_records;
+        self.additional_records = entry.additional_records;
+        self.answers = entry.answers.clone();
+    }
+
+    /// Writes the response into `buf`, returning the number of bytes
+    /// written. If the full answer doesn't fit, records are dropped from
+    /// the end, the section counts are adjusted to match what was
+    /// actually written, and the TC flag is set so the client knows to
+    /// retry over TCP
+    fn serialise(self, buf: &mut [u8]) -> Result<usize> {
 
         let mut buf_ptr = 0;
 
         // This is the data to be added to our send buffer using a helper
-        // function that keeps track of lengths
+        // function that keeps track of lengths. The header fields written
+        // here (flags and the section counts) get patched below once we
+        // know whether everything actually fit
         let fields = [
             &self.transaction_id,
             &self.flags.to_be_bytes(),
@@ -249,37 +853,724 @@ impl Dns {
             &self.answer_records.to_be_bytes(),
             &self.authority_records.to_be_bytes(),
             &self.additional_records.to_be_bytes(),
-            &self.query.domain_name[..self.query.domain_name_len],
+            self.query.name.as_bytes(),
             &self.query.ty.to_be_bytes(),
             &self.query.class.to_be_bytes(),
         ];
-        
+
         for field in fields {
             Self::append_to_buffer(buf, &mut buf_ptr, field);
         }
-        
-        // If we are responding as resolver
-        if let Some(answer) = self.answer{
-            let fields: [&[u8]; 6] = [
-                &answer.name,
-                &answer.ty.to_be_bytes(),
-                &answer.class.to_be_bytes(),
-                &answer.ttl.to_be_bytes(),
-                &answer.len.to_be_bytes(),
-                &answer.address,
-            ];
-            for field in fields {
-                Self::append_to_buffer(buf, &mut buf_ptr, field);
+
+        // Answer, authority and additional records, in order, stopping
+        // (rather than panicking on a buffer overrun) at the first record
+        // that doesn't fit
+        let mut written = 0;
+        for answer in &self.answers {
+            if buf_ptr + answer.wire_len() > buf.len() {
+                break;
             }
-        } 
+
+            Self::append_to_buffer(buf, &mut buf_ptr, answer.name.as_bytes());
+            Self::append_to_buffer(buf, &mut buf_ptr, &answer.data.ty().to_be_bytes());
+            Self::append_to_buffer(buf, &mut buf_ptr, &answer.class.to_be_bytes());
+            Self::append_to_buffer(buf, &mut buf_ptr, &answer.ttl.to_be_bytes());
+
+            // RDLENGTH is written once we know how many bytes the RDATA
+            // serialiser actually wrote
+            let rdlength_at = buf_ptr;
+            buf_ptr += 2;
+            let rdlength = answer.data.serialise(buf, &mut buf_ptr);
+            buf[rdlength_at..rdlength_at + 2].copy_from_slice(&rdlength.to_be_bytes());
+
+            written += 1;
+        }
+
+        let truncated = written < self.answers.len();
+        let (answer_records, authority_records, additional_records) = if truncated {
+            let answer_records = written.min(self.answer_records as usize);
+            let written = written - answer_records;
+            let authority_records = written.min(self.authority_records as usize);
+            let written = written - authority_records;
+            let additional_records = written.min(self.additional_records as usize);
+            (answer_records as u16, authority_records as u16, additional_records as u16)
+        } else {
+            (self.answer_records, self.authority_records, self.additional_records)
+        };
+        let flags = if truncated {
+            self.flags | Self::DNS_FLAG_TRUNCATED
+        } else {
+            self.flags
+        };
+
+        buf[2..4].copy_from_slice(&flags.to_be_bytes());
+        buf[6..8].copy_from_slice(&answer_records.to_be_bytes());
+        buf[8..10].copy_from_slice(&authority_records.to_be_bytes());
+        buf[10..12].copy_from_slice(&additional_records.to_be_bytes());
 
         Ok(buf_ptr)
     }
 
-    fn append_to_buffer(buf: &mut [u8; 512], buf_ptr: &mut usize, bytes: &[u8]){
+    fn append_to_buffer(buf: &mut [u8], buf_ptr: &mut usize, bytes: &[u8]){
         let bytes_len = bytes.len();
-        buf[*buf_ptr .. *buf_ptr + bytes_len].copy_from_slice(&bytes);
+        buf[*buf_ptr .. *buf_ptr + bytes_len].copy_from_slice(bytes);
         *buf_ptr += bytes_len;
     }
 }
 
+/// SOA fields for a locally authoritative [Zone]
+#[derive(Debug, Clone)]
+struct Soa {
+    m_name: String,
+    r_name: String,
+    serial: u32,
+    refresh: u32,
+    retry: u32,
+    expire: u32,
+    minimum: u32,
+}
+
+/// A zone this server is authoritative for: queries for names inside
+/// `origin` are answered from `records` and never forwarded upstream
+#[derive(Debug, Clone)]
+struct Zone {
+    origin: Vec<u8>,
+    soa: Soa,
+    records: HashMap<(Vec<u8>, u16, u16), Answer>,
+}
+
+impl Zone {
+    /// Look up a record for `query` in this zone. `None` means the zone is
+    /// authoritative for the name but holds no such record (NXDOMAIN)
+    fn lookup(&self, query: &Query) -> Option<Answer> {
+        self.records
+            .get(&(query.name.key_bytes(), query.ty, query.class))
+            .cloned()
+    }
+
+    /// Build this zone's SOA as an authority-section [Answer], for the
+    /// authority section of an NXDOMAIN response (RFC 1035 §3.3.13,
+    /// RFC 2308 §3)
+    fn soa_answer(&self) -> Answer {
+        Answer {
+            name: Name::from_wire_bytes(&self.origin),
+            class: Authority::CLASS_IN,
+            ttl: self.soa.minimum,
+            data: RecordData::SOA {
+                m_name: Name::from_str(&self.soa.m_name).unwrap_or_else(|_| Name::root()),
+                r_name: Name::from_str(&self.soa.r_name).unwrap_or_else(|_| Name::root()),
+                serial: self.soa.serial,
+                refresh: self.soa.refresh,
+                retry: self.soa.retry,
+                expire: self.soa.expire,
+                minimum: self.soa.minimum,
+            },
+        }
+    }
+}
+
+/// Store of all [Zone]s this server is authoritative for, loaded from
+/// [ZONE_FILE] at startup
+#[derive(Debug, Default)]
+struct Authority {
+    zones: Vec<Zone>,
+}
+
+impl Authority {
+    const CLASS_IN: u16 = 1;
+
+    /// Parse a simple zone file: `$ORIGIN` starts a new zone, `$SOA` sets
+    /// its SOA fields, and every other non-blank, non-comment line is a
+    /// `name ty class ttl rdata` record. Only `A` records of class `IN` are
+    /// loaded; records of any other type or class are skipped. Missing or
+    /// unreadable files just mean no zones are authoritative, so the
+    /// server forwards everything
+    fn load(path: &str) -> Self {
+        let Ok(contents) = std::fs::read_to_string(path) else {
+            return Self::default();
+        };
+
+        let mut zones = Vec::new();
+        let mut origin: Option<String> = None;
+        let mut soa: Option<Soa> = None;
+        let mut records = HashMap::new();
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+
+            if fields[0] == "$ORIGIN" {
+                if let (Some(origin), Some(soa)) = (origin.take(), soa.take())
+                    && let Ok(origin) = Name::from_str(&origin)
+                {
+                    zones.push(Zone {
+                        origin: origin.key_bytes(),
+                        soa,
+                        records: std::mem::take(&mut records),
+                    });
+                }
+                origin = Some(fields[1].to_string());
+                continue;
+            }
+
+            if fields[0] == "$SOA" {
+                soa = Some(Soa {
+                    m_name: fields[1].to_string(),
+                    r_name: fields[2].to_string(),
+                    serial: fields[3].parse().unwrap(),
+                    refresh: fields[4].parse().unwrap(),
+                    retry: fields[5].parse().unwrap(),
+                    expire: fields[6].parse().unwrap(),
+                    minimum: fields[7].parse().unwrap(),
+                });
+                continue;
+            }
+
+            let Some(origin) = &origin else {
+                continue;
+            };
+            let [name, ty, class, ttl, rdata] = fields[..] else {
+                continue;
+            };
+            if ty != "A" || class != "IN" {
+                continue;
+            }
+            let fqdn = if name == "@" {
+                origin.clone()
+            } else {
+                format!("{name}.{origin}")
+            };
+            let Ok(name) = Name::from_str(&fqdn) else {
+                continue;
+            };
+            let Ok(address) = rdata
+                .split('.')
+                .map(|octet| octet.parse::<u8>())
+                .collect::<std::result::Result<Vec<u8>, _>>()
+            else {
+                continue;
+            };
+            let [a, b, c, d] = address[..] else {
+                continue;
+            };
+
+            records.insert(
+                (name.key_bytes(), QueryType::A.to_num(), Self::CLASS_IN),
+                Answer {
+                    name,
+                    class: Self::CLASS_IN,
+                    ttl: ttl.parse().unwrap(),
+                    data: RecordData::A(Ipv4Addr::new(a, b, c, d)),
+                },
+            );
+        }
+
+        if let (Some(origin), Some(soa)) = (origin, soa)
+            && let Ok(origin) = Name::from_str(&origin)
+        {
+            zones.push(Zone {
+                origin: origin.key_bytes(),
+                soa,
+                records,
+            });
+        }
+
+        Self { zones }
+    }
+
+    /// Split a wire-format name (length-prefixed labels terminated by a
+    /// zero-length label) into its labels, in order
+    fn labels(wire_name: &[u8]) -> Vec<&[u8]> {
+        let mut labels = Vec::new();
+        let mut cursor = 0;
+        while let Some(&label_len) = wire_name.get(cursor) {
+            let label_len = label_len as usize;
+            if label_len == 0 {
+                break;
+            }
+            labels.push(&wire_name[cursor + 1..cursor + 1 + label_len]);
+            cursor += 1 + label_len;
+        }
+        labels
+    }
+
+    /// Find the zone that is authoritative for `domain_name`, i.e. the one
+    /// whose origin is a suffix of it. Names are matched label by label
+    /// rather than as a raw byte suffix, so a label whose content happens
+    /// to share trailing bytes with `origin` (e.g. a `no\x07example` label
+    /// in front of `com`, whose wire bytes end in `\x07example\x03com\x00`
+    /// just like `example.com`'s) isn't mistaken for a subdomain of it. Names are
+    /// matched case-insensitively (RFC 1035 §3.1); `zone.origin` is
+    /// already folded to lowercase when the zone is loaded
+    fn find_zone(&self, domain_name: &[u8]) -> Option<&Zone> {
+        let domain_name: Vec<u8> = domain_name.iter().map(u8::to_ascii_lowercase).collect();
+        let domain_labels = Self::labels(&domain_name);
+        self.zones.iter().find(|zone| {
+            let origin_labels = Self::labels(&zone.origin);
+            domain_labels.len() >= origin_labels.len()
+                && domain_labels[domain_labels.len() - origin_labels.len()..] == origin_labels[..]
+        })
+    }
+}
+
+/// A forwarded response held in the [Cache], with enough of the original
+/// [Dns] response to rebuild it for a later hit
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    answers: Vec<Answer>,
+    answer_records: u16,
+    authority_records: u16,
+    additional_records: u16,
+    /// Lowest real-TTL (seconds) across every section as it was when this
+    /// entry was cached, used only to decide when the whole entry is
+    /// evicted; each record's own TTL is decremented individually on read
+    ttl: u32,
+    cached_at: Instant,
+}
+
+/// Caches forwarded responses keyed on `(domain_name, ty, class)` so
+/// repeated lookups for the same name don't round-trip to the upstream
+/// resolver until their TTL expires
+#[derive(Debug, Default)]
+struct Cache {
+    entries: HashMap<(Vec<u8>, u16, u16), CacheEntry>,
+}
+
+impl Cache {
+    /// Cache keys are folded to lowercase so `WWW.example.com` and
+    /// `www.example.com` share a cache entry, matching DNS's
+    /// case-insensitive name comparison (RFC 1035 §3.1)
+    fn key(query: &Query) -> (Vec<u8>, u16, u16) {
+        (query.name.key_bytes(), query.ty, query.class)
+    }
+
+    /// Look up a live entry for `key`, with every record's TTL decremented
+    /// individually by however long it's been cached. Expired entries are
+    /// evicted and treated as a miss. Records are decremented from their
+    /// own original TTL (not a single entry-wide value), across the
+    /// answer, authority and additional sections alike, since NS/glue
+    /// records carry real TTLs wherever they appear in the response. The
+    /// EDNS(0) OPT pseudo-record is the only record left alone: its TTL
+    /// field is reused for extended-RCODE/flags rather than a real TTL
+    fn get(&mut self, key: &(Vec<u8>, u16, u16)) -> Option<CacheEntry> {
+        let entry = self.entries.get(key)?;
+        let elapsed = entry.cached_at.elapsed().as_secs() as u32;
+        if elapsed >= entry.ttl {
+            self.entries.remove(key);
+            return None;
+        }
+
+        let mut entry = entry.clone();
+        for answer in entry.answers.iter_mut() {
+            if matches!(answer.data, RecordData::Opt(_)) {
+                continue;
+            }
+            answer.ttl = answer.ttl.saturating_sub(elapsed);
+        }
+        Some(entry)
+    }
+
+    /// Cache `response` under `key`. Responses with no real-TTL records,
+    /// or whose lowest such TTL is zero, aren't worth caching. The
+    /// eviction TTL is the lowest TTL across every section (answer,
+    /// authority and additional alike), ignoring only the EDNS(0) OPT
+    /// pseudo-record, which carries no TTL of its own
+    fn insert(&mut self, key: (Vec<u8>, u16, u16), response: &Dns) {
+        let Some(ttl) = response.answers
+            .iter()
+            .filter(|answer| !matches!(answer.data, RecordData::Opt(_)))
+            .map(|answer| answer.ttl)
+            .min()
+        else {
+            return;
+        };
+        if ttl == 0 {
+            return;
+        }
+
+        self.entries.insert(key, CacheEntry {
+            answers: response.answers.clone(),
+            answer_records: response.answer_records,
+            authority_records: response.authority_records,
+            additional_records: response.additional_records,
+            ttl,
+            cached_at: Instant::now(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rejects_short_packet_instead_of_panicking() {
+        assert!(matches!(Dns::parse(&[0x00, 0x01]), Err(Error::TruncatedPacket)));
+    }
+
+    #[test]
+    fn parse_rejects_header_promising_records_it_doesnt_have() {
+        // A well-formed header and root-name question, claiming one
+        // additional record (e.g. an EDNS(0) OPT) that was never actually
+        // appended
+        let packet = [
+            0x13, 0x37, // transaction id
+            0x01, 0x00, // flags
+            0x00, 0x01, // questions
+            0x00, 0x00, // answer_records
+            0x00, 0x00, // authority_records
+            0x00, 0x01, // additional_records
+            0x00, // question name: root
+            0x00, 0x01, // qtype A
+            0x00, 0x01, // qclass IN
+        ];
+        assert!(matches!(Dns::parse(&packet), Err(Error::TruncatedPacket)));
+    }
+
+    #[test]
+    fn cache_get_does_not_rewrite_opt_pseudo_record_ttl() {
+        let name = Name::from_str("example.com").unwrap();
+        let query = Query { name, ty: QueryType::A.to_num(), class: Authority::CLASS_IN };
+        let answer = Answer {
+            name,
+            class: Authority::CLASS_IN,
+            ttl: 300,
+            data: RecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+        };
+        let opt = Dns::opt_record(Dns::MAX_UDP_PAYLOAD_SIZE);
+        let response = Dns {
+            transaction_id: [0, 0],
+            flags: 0,
+            questions: 1,
+            answer_records: 1,
+            authority_records: 0,
+            additional_records: 1,
+            query,
+            answers: vec![answer, opt],
+        };
+
+        let mut cache = Cache::default();
+        let key = Cache::key(&query);
+        cache.insert(key.clone(), &response);
+
+        let entry = cache.get(&key).unwrap();
+        assert_eq!(entry.answers[0].ttl, 300);
+        assert_eq!(entry.answers[1].ttl, 0);
+    }
+
+    /// Serialise a response carrying a single answer with `data` as its
+    /// RDATA, then parse it back, returning the round-tripped RDATA
+    fn round_trip(data: RecordData) -> RecordData {
+        let name = Name::from_str("example.com").unwrap();
+        let response = Dns {
+            transaction_id: [0, 0],
+            flags: 0,
+            questions: 1,
+            answer_records: 1,
+            authority_records: 0,
+            additional_records: 0,
+            query: Query { name, ty: QueryType::A.to_num(), class: Authority::CLASS_IN },
+            answers: vec![Answer { name, class: Authority::CLASS_IN, ttl: 300, data }],
+        };
+
+        let mut buf = vec![0u8; Dns::MAX_UDP_PAYLOAD_SIZE as usize];
+        let len = response.serialise(&mut buf).unwrap();
+        Dns::parse(&buf[..len]).unwrap().answers.remove(0).data
+    }
+
+    #[test]
+    fn round_trips_aaaa_record() {
+        let addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        assert!(matches!(round_trip(RecordData::AAAA(addr)), RecordData::AAAA(got) if got == addr));
+    }
+
+    #[test]
+    fn round_trips_cname_record() {
+        let target = Name::from_str("canonical.example.com").unwrap();
+        let got = round_trip(RecordData::CNAME(target));
+        assert!(matches!(got, RecordData::CNAME(got) if got.as_bytes() == target.as_bytes()));
+    }
+
+    #[test]
+    fn round_trips_ns_record() {
+        let target = Name::from_str("ns1.example.com").unwrap();
+        let got = round_trip(RecordData::NS(target));
+        assert!(matches!(got, RecordData::NS(got) if got.as_bytes() == target.as_bytes()));
+    }
+
+    #[test]
+    fn round_trips_mx_record() {
+        let exchange = Name::from_str("mail.example.com").unwrap();
+        let got = round_trip(RecordData::MX { preference: 10, exchange });
+        assert!(matches!(
+            got,
+            RecordData::MX { preference: 10, exchange: got } if got.as_bytes() == exchange.as_bytes()
+        ));
+    }
+
+    #[test]
+    fn round_trips_soa_record() {
+        let m_name = Name::from_str("ns1.example.com").unwrap();
+        let r_name = Name::from_str("hostmaster.example.com").unwrap();
+        let got = round_trip(RecordData::SOA {
+            m_name,
+            r_name,
+            serial: 2024010100,
+            refresh: 3600,
+            retry: 900,
+            expire: 604800,
+            minimum: 300,
+        });
+        assert!(matches!(
+            got,
+            RecordData::SOA { m_name: got_m, r_name: got_r, serial: 2024010100, refresh: 3600, retry: 900, expire: 604800, minimum: 300 }
+                if got_m.as_bytes() == m_name.as_bytes() && got_r.as_bytes() == r_name.as_bytes()
+        ));
+    }
+
+    #[test]
+    fn round_trips_ptr_record() {
+        let target = Name::from_str("host.example.com").unwrap();
+        let got = round_trip(RecordData::PTR(target));
+        assert!(matches!(got, RecordData::PTR(got) if got.as_bytes() == target.as_bytes()));
+    }
+
+    #[test]
+    fn round_trips_srv_record() {
+        let target = Name::from_str("srv1.example.com").unwrap();
+        let got = round_trip(RecordData::SRV { priority: 1, weight: 2, port: 443, target });
+        assert!(matches!(
+            got,
+            RecordData::SRV { priority: 1, weight: 2, port: 443, target: got } if got.as_bytes() == target.as_bytes()
+        ));
+    }
+
+    #[test]
+    fn edns_udp_payload_size_returns_the_advertised_size() {
+        let name = Name::from_str("example.com").unwrap();
+        let query = Query { name, ty: QueryType::A.to_num(), class: Authority::CLASS_IN };
+        let request = Dns::request(query, [0, 0]);
+        assert_eq!(request.edns_udp_payload_size(), Some(Dns::MAX_UDP_PAYLOAD_SIZE));
+    }
+
+    #[test]
+    fn edns_udp_payload_size_is_none_without_an_opt_record() {
+        let name = Name::from_str("example.com").unwrap();
+        let query = Query { name, ty: QueryType::A.to_num(), class: Authority::CLASS_IN };
+        let request = Dns {
+            transaction_id: [0, 0],
+            flags: 0x100,
+            questions: 1,
+            answer_records: 0,
+            authority_records: 0,
+            additional_records: 0,
+            query,
+            answers: Vec::new(),
+        };
+        assert_eq!(request.edns_udp_payload_size(), None);
+    }
+
+    /// Mirrors the clamp applied to a client's advertised EDNS(0) payload
+    /// size in `main`, so it has a unit test without pulling `main` apart
+    fn clamped_client_udp_payload_size(request: &Dns) -> usize {
+        request
+            .edns_udp_payload_size()
+            .unwrap_or(Dns::DEFAULT_UDP_PAYLOAD_SIZE)
+            .clamp(Dns::DEFAULT_UDP_PAYLOAD_SIZE, Dns::MAX_UDP_PAYLOAD_SIZE) as usize
+    }
+
+    #[test]
+    fn client_udp_payload_size_clamps_below_the_legacy_default_up() {
+        let name = Name::from_str("example.com").unwrap();
+        let query = Query { name, ty: QueryType::A.to_num(), class: Authority::CLASS_IN };
+        let request = Dns {
+            transaction_id: [0, 0],
+            flags: 0x100,
+            questions: 1,
+            answer_records: 0,
+            authority_records: 0,
+            additional_records: 1,
+            query,
+            answers: vec![Dns::opt_record(128)],
+        };
+        assert_eq!(clamped_client_udp_payload_size(&request), Dns::DEFAULT_UDP_PAYLOAD_SIZE as usize);
+    }
+
+    #[test]
+    fn client_udp_payload_size_clamps_above_our_max_down() {
+        let name = Name::from_str("example.com").unwrap();
+        let query = Query { name, ty: QueryType::A.to_num(), class: Authority::CLASS_IN };
+        let request = Dns {
+            transaction_id: [0, 0],
+            flags: 0x100,
+            questions: 1,
+            answer_records: 0,
+            authority_records: 0,
+            additional_records: 1,
+            query,
+            answers: vec![Dns::opt_record(u16::MAX)],
+        };
+        assert_eq!(clamped_client_udp_payload_size(&request), Dns::MAX_UDP_PAYLOAD_SIZE as usize);
+    }
+
+    #[test]
+    fn client_udp_payload_size_falls_back_to_legacy_default_without_edns() {
+        let name = Name::from_str("example.com").unwrap();
+        let query = Query { name, ty: QueryType::A.to_num(), class: Authority::CLASS_IN };
+        let request = Dns {
+            transaction_id: [0, 0],
+            flags: 0x100,
+            questions: 1,
+            answer_records: 0,
+            authority_records: 0,
+            additional_records: 0,
+            query,
+            answers: Vec::new(),
+        };
+        assert_eq!(clamped_client_udp_payload_size(&request), Dns::DEFAULT_UDP_PAYLOAD_SIZE as usize);
+    }
+
+    #[test]
+    fn serialise_truncates_and_sets_tc_flag_when_answers_dont_fit() {
+        let name = Name::from_str("example.com").unwrap();
+        let answer = Answer {
+            name,
+            class: Authority::CLASS_IN,
+            ttl: 300,
+            data: RecordData::AAAA(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+        };
+        let answers = vec![answer.clone(), answer.clone(), answer.clone()];
+        let answer_wire_len = answer.wire_len();
+
+        let build = |answers: Vec<Answer>| Dns {
+            transaction_id: [0, 0],
+            flags: 0,
+            questions: 1,
+            answer_records: answers.len() as u16,
+            authority_records: 0,
+            additional_records: 0,
+            query: Query { name, ty: QueryType::AAAA.to_num(), class: Authority::CLASS_IN },
+            answers,
+        };
+
+        let mut full_buf = vec![0u8; Dns::MAX_UDP_PAYLOAD_SIZE as usize];
+        let full_len = build(answers.clone()).serialise(&mut full_buf).unwrap();
+        let header_len = full_len - answers.len() * answer_wire_len;
+
+        // Only enough room for the header, question, and a single answer
+        let mut small_buf = vec![0u8; header_len + answer_wire_len];
+        let len = build(answers.clone()).serialise(&mut small_buf).unwrap();
+        let response = Dns::parse(&small_buf[..len]).unwrap();
+
+        assert_eq!(response.flags & Dns::DNS_FLAG_TRUNCATED, Dns::DNS_FLAG_TRUNCATED);
+        assert_eq!(response.answer_records, 1);
+        assert_eq!(response.answers.len(), 1);
+
+        // The same response, serialised into a TCP-sized buffer with room
+        // for everything, isn't truncated: this is what makes retrying a
+        // truncated UDP response over TCP actually work
+        let mut tcp_buf = vec![0u8; u16::MAX as usize];
+        let len = build(answers).serialise(&mut tcp_buf).unwrap();
+        let response = Dns::parse(&tcp_buf[..len]).unwrap();
+
+        assert_eq!(response.flags & Dns::DNS_FLAG_TRUNCATED, 0);
+        assert_eq!(response.answer_records, 3);
+        assert_eq!(response.answers.len(), 3);
+    }
+
+    #[test]
+    fn read_name_follows_a_compression_pointer() {
+        // "example.com" spelled out at offset 0, followed by a pointer
+        // back to it at offset 13
+        let packet = [
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0x00,
+            0xC0, 0x00,
+        ];
+        let (name, end) = Dns::read_name(&packet, 13).unwrap();
+        assert_eq!(name.as_bytes(), Name::from_str("example.com").unwrap().as_bytes());
+        // The cursor past the name as encoded at `start` stops right after
+        // the 2-byte pointer, not wherever the jump landed
+        assert_eq!(end, 15);
+    }
+
+    #[test]
+    fn read_name_rejects_a_pointer_loop() {
+        // A pointer at offset 0 that points right back to itself
+        let packet = [0xC0, 0x00];
+        assert!(matches!(
+            Dns::read_name(&packet, 0),
+            Err(Error::TooManyNameIndirections)
+        ));
+    }
+
+    #[test]
+    fn cache_get_decrements_each_records_own_ttl() {
+        let name = Name::from_str("example.com").unwrap();
+        let query = Query { name, ty: QueryType::NS.to_num(), class: Authority::CLASS_IN };
+        let short_lived = Answer {
+            name,
+            class: Authority::CLASS_IN,
+            ttl: 10,
+            data: RecordData::A(Ipv4Addr::new(127, 0, 0, 1)),
+        };
+        let ns_name = Name::from_str("ns1.example.com").unwrap();
+        let long_lived_authority = Answer {
+            name,
+            class: Authority::CLASS_IN,
+            ttl: 3600,
+            data: RecordData::NS(ns_name),
+        };
+        let response = Dns {
+            transaction_id: [0, 0],
+            flags: 0,
+            questions: 1,
+            answer_records: 1,
+            authority_records: 1,
+            additional_records: 0,
+            query,
+            answers: vec![short_lived, long_lived_authority],
+        };
+
+        let mut cache = Cache::default();
+        let key = Cache::key(&query);
+        cache.insert(key.clone(), &response);
+
+        let entry = cache.get(&key).unwrap();
+        // Neither TTL has been overwritten with the other's value
+        assert_eq!(entry.answers[0].ttl, 10);
+        assert_eq!(entry.answers[1].ttl, 3600);
+    }
+
+    #[test]
+    fn find_zone_does_not_match_on_raw_byte_suffix() {
+        let authority = Authority {
+            zones: vec![Zone {
+                origin: Name::from_str("example.com").unwrap().key_bytes(),
+                soa: Soa {
+                    m_name: "ns.example.com".to_string(),
+                    r_name: "hostmaster.example.com".to_string(),
+                    serial: 1,
+                    refresh: 1,
+                    retry: 1,
+                    expire: 1,
+                    minimum: 1,
+                },
+                records: HashMap::new(),
+            }],
+        };
+
+        // Its wire bytes end in the same raw bytes as "example.com"'s (the
+        // 0x07 inside the first label reads like a length byte), but it is
+        // a different, unrelated name: not a subdomain of it
+        let spoofed = Name::from_str("no\x07example.com").unwrap();
+        assert!(authority.find_zone(spoofed.as_bytes()).is_none());
+
+        // A genuine subdomain still matches
+        let legit = Name::from_str("www.example.com").unwrap();
+        assert!(authority.find_zone(legit.as_bytes()).is_some());
+    }
+}